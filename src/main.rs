@@ -1,15 +1,101 @@
 use eyre::{Report, WrapErr};
-use std::io::{Seek, Write};
+use std::io::{Read, Seek, Write};
 use std::str::FromStr;
 use tokio_stream::StreamExt;
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 
 type Result<T> = std::result::Result<T, Report>;
 
+/// S3 requires multipart parts (other than the last one) to be at least 5 MiB.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+fn parse_part_size(s: &str) -> std::result::Result<u64, String> {
+    let part_size: u64 = s.parse().map_err(|_| format!("'{s}' is not a valid number of bytes"))?;
+    if part_size < MIN_PART_SIZE {
+        return Err(format!(
+            "part size must be at least {MIN_PART_SIZE} bytes (5 MiB), got {part_size}"
+        ));
+    }
+    Ok(part_size)
+}
+
 #[derive(Parser)]
 struct Args {
+    /// The `s3://bucket/key` to open. Not used when a subcommand is given.
+    object: Option<String>,
+
+    /// Don't upload changes back to S3, even if the file was edited.
+    #[arg(long)]
+    read_only: bool,
+
+    /// Size in bytes of each part when uploading via multipart. Must be at least 5 MiB.
+    #[arg(long, default_value_t = MIN_PART_SIZE, value_parser = parse_part_size)]
+    part_size: u64,
+
+    /// Files larger than this (in bytes) are uploaded via multipart instead of a single `put_object` call.
+    #[arg(long, default_value_t = 8 * 1024 * 1024)]
+    multipart_threshold: u64,
+
+    /// Custom S3 endpoint, for S3-compatible stores such as MinIO, Wasabi or Backblaze B2.
+    #[arg(long, env = "S3_OPEN_ENDPOINT")]
+    endpoint: Option<String>,
+
+    /// AWS region to use, overriding the region from the environment/profile.
+    #[arg(long, env = "S3_OPEN_REGION")]
+    region: Option<String>,
+
+    /// Use path-style addressing (`endpoint/bucket/key`) instead of virtual-hosted-style,
+    /// required by most S3-compatible stores.
+    #[arg(long, env = "S3_OPEN_FORCE_PATH_STYLE")]
+    force_path_style: bool,
+
+    /// Error out instead of prompting with a picker when `object` names a prefix rather than
+    /// an exact key. Useful for scripting.
+    #[arg(long)]
+    no_interactive: bool,
+
+    /// Editor to spawn, overriding `$VISUAL`/`$EDITOR`. Falls back to `nvim`.
+    #[arg(long)]
+    editor: Option<String>,
+
+    /// Extra argument to pass to the editor. May be given multiple times.
+    #[arg(long = "editor-arg")]
+    editor_args: Vec<String>,
+
+    /// After editing, make the upload conditional on the object being unchanged in S3 since
+    /// it was downloaded, refusing to overwrite it if it changed while the editor was open.
+    #[arg(long)]
+    watch: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print a presigned URL for an object instead of downloading and editing it.
+    Presign(PresignArgs),
+}
+
+#[derive(clap::Args)]
+struct PresignArgs {
+    /// The `s3://bucket/key` to presign.
     object: String,
+
+    /// Whether the URL should allow downloading (`get`) or uploading (`put`) the object.
+    #[arg(long, value_enum, default_value_t = PresignMethod::Get)]
+    method: PresignMethod,
+
+    /// How long the presigned URL stays valid, e.g. `15m`, `1h`, `30s`.
+    #[arg(long, default_value = "15m")]
+    expires_in: humantime::Duration,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PresignMethod {
+    Get,
+    Put,
 }
 
 #[derive(Debug)]
@@ -19,6 +105,10 @@ struct S3Info {
     extension: Option<String>,
 }
 
+/// Parses `s3://bucket/key`. This is the only addressing scheme we support: S3-compatible
+/// providers (MinIO, Wasabi, Backblaze B2, ...) are reached by pointing `--endpoint` at them
+/// while still referring to objects as `s3://bucket/key`, rather than by accepting each
+/// provider's own URL form (`https://...`, `gs://`-style, etc).
 impl FromStr for S3Info {
     type Err = Report;
 
@@ -46,11 +136,28 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
     let args = Args::parse();
-    let s3_info: S3Info = args.object.parse().wrap_err("invalid S3 url")?;
+    let client = build_client(&args).await;
+
+    if let Some(Command::Presign(presign_args)) = &args.command {
+        return presign(&client, presign_args).await;
+    }
+
+    let object = args
+        .object
+        .as_ref()
+        .ok_or_else(|| eyre::eyre!("missing required argument: object"))?;
+    let mut s3_info: S3Info = object.parse().wrap_err("invalid S3 url")?;
     tracing::debug!(?s3_info, "extracted s3 information");
 
-    let config = aws_config::load_from_env().await;
-    let client = aws_sdk_s3::Client::new(&config);
+    if s3_info.key.is_empty()
+        || s3_info.key.ends_with('/')
+        || !object_exists(&client, &s3_info).await?
+    {
+        let key = pick_key(&client, &s3_info.bucket, &s3_info.key, args.no_interactive).await?;
+        s3_info.extension = key.rsplit_once('.').map(|(_, ext)| ext.to_string());
+        s3_info.key = key;
+        tracing::debug!(?s3_info, "resolved prefix to object");
+    }
 
     let mut res = client
         .get_object()
@@ -60,6 +167,10 @@ async fn main() -> Result<()> {
         .await
         .wrap_err("fetching file from S3")?;
 
+    let content_type = res.content_type().map(|s| s.to_string());
+    let content_encoding = res.content_encoding().map(|s| s.to_string());
+    let original_etag = res.e_tag().map(|s| s.to_string());
+
     let mut tf = {
         if let Some(ref ext) = s3_info.extension {
             tempfile::Builder::new()
@@ -71,24 +182,406 @@ async fn main() -> Result<()> {
     };
     tracing::debug!(path = ?tf.path(), "created temporary file");
 
-    let mut bytes_written = 0;
+    let mut original_bytes = Vec::new();
     while let Some(bytes) = res.body.try_next().await? {
-        bytes_written += tf.write(&bytes)?;
+        original_bytes.extend_from_slice(&bytes);
+        tf.write_all(&bytes)?;
     }
 
     tf.seek(std::io::SeekFrom::Start(0))?;
-    tracing::debug!(%bytes_written, "file contents written");
+    tracing::debug!(bytes_written = original_bytes.len(), "file contents written");
 
     // open editor
+    let editor = resolve_editor(&args);
+    tracing::debug!(%editor, "resolved editor");
     let tfile_path = tf.path().as_os_str();
-    let mut child = std::process::Command::new("nvim")
-        .args(&[tfile_path])
+    let mut child = std::process::Command::new(&editor)
+        .args(&args.editor_args)
+        .arg(tfile_path)
         .spawn()
-        .wrap_err("spawning editor")?;
+        .wrap_err_with(|| format!("spawning editor '{editor}'"))?;
     let status = child.wait().wrap_err("waiting for editor")?;
     if !status.success() {
         eyre::bail!("editor exited unsuccessfully");
     }
 
+    if args.read_only {
+        tracing::debug!("--read-only set, not checking for changes");
+        return Ok(());
+    }
+
+    let mut edited_bytes = Vec::new();
+    std::fs::File::open(tf.path())
+        .wrap_err("reopening edited file")?
+        .read_to_end(&mut edited_bytes)
+        .wrap_err("reading edited file")?;
+
+    if edited_bytes == original_bytes {
+        tracing::debug!("file unchanged, nothing to upload");
+        return Ok(());
+    }
+
+    // In --watch mode, make the upload conditional on the object still having the ETag we
+    // downloaded: if it changed in S3 while the editor was open, S3 rejects the write with a
+    // precondition failure instead of us silently clobbering the newer remote copy.
+    let if_match = if args.watch { original_etag.clone() } else { None };
+
+    tracing::info!(bucket = %s3_info.bucket, key = %s3_info.key, "uploading changes back to S3");
+    let upload_result = if edited_bytes.len() as u64 > args.multipart_threshold {
+        upload_multipart(
+            &client,
+            &s3_info,
+            &edited_bytes,
+            args.part_size,
+            content_type,
+            content_encoding,
+            if_match,
+        )
+        .await
+    } else {
+        upload_single(
+            &client,
+            &s3_info,
+            edited_bytes,
+            content_type,
+            content_encoding,
+            if_match,
+        )
+        .await
+    };
+
+    upload_result.wrap_err_with(|| {
+        if args.watch {
+            format!(
+                "uploading edited file to s3://{}/{} (if the object changed in S3 while you were \
+                 editing, this upload was refused to avoid overwriting it; re-run to fetch the \
+                 latest copy and re-apply your edits)",
+                s3_info.bucket, s3_info.key
+            )
+        } else {
+            format!("uploading edited file to s3://{}/{}", s3_info.bucket, s3_info.key)
+        }
+    })?;
+
     Ok(())
 }
+
+/// Resolves the editor to spawn: `--editor`, then `$VISUAL`, then `$EDITOR`, falling back to `nvim`.
+fn resolve_editor(args: &Args) -> String {
+    args.editor
+        .clone()
+        .or_else(|| std::env::var("VISUAL").ok())
+        .or_else(|| std::env::var("EDITOR").ok())
+        .unwrap_or_else(|| "nvim".to_string())
+}
+
+async fn build_client(args: &Args) -> aws_sdk_s3::Client {
+    let mut config_loader = aws_config::from_env();
+    if let Some(region) = &args.region {
+        config_loader = config_loader.region(aws_sdk_s3::config::Region::new(region.clone()));
+    }
+    let config = config_loader.load().await;
+
+    let mut s3_config = aws_sdk_s3::config::Builder::from(&config);
+    if let Some(endpoint) = &args.endpoint {
+        tracing::debug!(%endpoint, "using custom S3 endpoint");
+        s3_config = s3_config.endpoint_url(endpoint);
+    }
+    if args.force_path_style {
+        s3_config = s3_config.force_path_style(true);
+    }
+
+    aws_sdk_s3::Client::from_conf(s3_config.build())
+}
+
+/// Returns whether `s3_info.key` names an exact object. Any error from `head_object` (not
+/// just "not found") is treated as "no exact object exists", so we fall back to listing.
+async fn object_exists(client: &aws_sdk_s3::Client, s3_info: &S3Info) -> Result<bool> {
+    match client
+        .head_object()
+        .bucket(&s3_info.bucket)
+        .key(&s3_info.key)
+        .send()
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(err) => {
+            tracing::debug!(%err, "head_object failed, treating key as a prefix");
+            Ok(false)
+        }
+    }
+}
+
+/// Lists everything directly under `prefix` in `bucket`, paginating over continuation tokens.
+async fn list_under_prefix(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    prefix: &str,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let mut common_prefixes = Vec::new();
+    let mut keys = Vec::new();
+    let mut continuation_token = None;
+
+    loop {
+        let mut req = client
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .delimiter("/");
+        if let Some(token) = &continuation_token {
+            req = req.continuation_token(token);
+        }
+
+        let res = req.send().await.wrap_err("listing objects")?;
+        common_prefixes.extend(
+            res.common_prefixes()
+                .iter()
+                .filter_map(|p| p.prefix().map(|s| s.to_string())),
+        );
+        keys.extend(
+            res.contents()
+                .iter()
+                .filter_map(|o| o.key().map(|s| s.to_string())),
+        );
+
+        if res.is_truncated().unwrap_or(false) {
+            continuation_token = res.next_continuation_token().map(|s| s.to_string());
+        } else {
+            break;
+        }
+    }
+
+    Ok((common_prefixes, keys))
+}
+
+/// Interactively drills down from `start_prefix` until the user picks an exact key, or errors
+/// immediately if `no_interactive` is set.
+async fn pick_key(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    start_prefix: &str,
+    no_interactive: bool,
+) -> Result<String> {
+    let mut prefix = start_prefix.to_string();
+
+    loop {
+        if no_interactive {
+            eyre::bail!(
+                "'s3://{bucket}/{prefix}' is a prefix, not an object; pass an exact key or drop --no-interactive"
+            );
+        }
+
+        let (common_prefixes, keys) = list_under_prefix(client, bucket, &prefix).await?;
+
+        let mut items = common_prefixes;
+        items.extend(keys);
+        if items.is_empty() {
+            eyre::bail!("no objects found under prefix 's3://{bucket}/{prefix}'");
+        }
+
+        let selection = dialoguer::FuzzySelect::new()
+            .with_prompt(format!("s3://{bucket}/{prefix}"))
+            .items(&items)
+            .interact()
+            .wrap_err("prompting for object selection")?;
+
+        let chosen = items.into_iter().nth(selection).unwrap();
+        if chosen.ends_with('/') {
+            prefix = chosen;
+        } else {
+            return Ok(chosen);
+        }
+    }
+}
+
+async fn presign(client: &aws_sdk_s3::Client, args: &PresignArgs) -> Result<()> {
+    let s3_info: S3Info = args.object.parse().wrap_err("invalid S3 url")?;
+    let presign_config =
+        aws_sdk_s3::presigning::PresigningConfig::expires_in(args.expires_in.into())
+            .wrap_err("building presigning config")?;
+
+    let url = match args.method {
+        PresignMethod::Get => {
+            client
+                .get_object()
+                .bucket(&s3_info.bucket)
+                .key(&s3_info.key)
+                .presigned(presign_config)
+                .await
+                .wrap_err("presigning GET request")?
+                .uri()
+                .to_string()
+        }
+        PresignMethod::Put => {
+            client
+                .put_object()
+                .bucket(&s3_info.bucket)
+                .key(&s3_info.key)
+                .presigned(presign_config)
+                .await
+                .wrap_err("presigning PUT request")?
+                .uri()
+                .to_string()
+        }
+    };
+
+    println!("{url}");
+
+    Ok(())
+}
+
+async fn upload_single(
+    client: &aws_sdk_s3::Client,
+    s3_info: &S3Info,
+    body: Vec<u8>,
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    if_match: Option<String>,
+) -> Result<()> {
+    let mut put = client
+        .put_object()
+        .bucket(&s3_info.bucket)
+        .key(&s3_info.key)
+        .body(body.into());
+
+    if let Some(content_type) = content_type {
+        put = put.content_type(content_type);
+    }
+    if let Some(content_encoding) = content_encoding {
+        put = put.content_encoding(content_encoding);
+    }
+    if let Some(if_match) = if_match {
+        put = put.if_match(if_match);
+    }
+
+    put.send().await?;
+
+    Ok(())
+}
+
+/// Uploads `body` to `s3_info` in fixed-size parts, aborting the multipart upload on any
+/// failure so we don't leave orphaned parts behind incurring storage charges. This includes a
+/// precondition failure on `complete_multipart_upload` from `if_match` (set in `--watch` mode):
+/// the conflict still surfaces to the caller with the usual conflict-retry guidance, but now
+/// without orphaning the parts already uploaded for a large object.
+async fn upload_multipart(
+    client: &aws_sdk_s3::Client,
+    s3_info: &S3Info,
+    body: &[u8],
+    part_size: u64,
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    if_match: Option<String>,
+) -> Result<()> {
+    let mut create = client
+        .create_multipart_upload()
+        .bucket(&s3_info.bucket)
+        .key(&s3_info.key);
+
+    if let Some(content_type) = content_type {
+        create = create.content_type(content_type);
+    }
+    if let Some(content_encoding) = content_encoding {
+        create = create.content_encoding(content_encoding);
+    }
+
+    let create_res = create
+        .send()
+        .await
+        .wrap_err("starting multipart upload")?;
+    let upload_id = create_res
+        .upload_id()
+        .ok_or_else(|| eyre::eyre!("create_multipart_upload response missing upload_id"))?;
+
+    let result = complete_multipart(client, s3_info, body, part_size, upload_id, if_match).await;
+
+    if let Err(err) = result {
+        tracing::warn!(%upload_id, "aborting multipart upload after error");
+        if let Err(abort_err) = client
+            .abort_multipart_upload()
+            .bucket(&s3_info.bucket)
+            .key(&s3_info.key)
+            .upload_id(upload_id)
+            .send()
+            .await
+        {
+            tracing::error!(%upload_id, %abort_err, "failed to abort multipart upload; parts may be left orphaned");
+        }
+
+        return Err(err);
+    }
+
+    result
+}
+
+/// Uploads every part and completes the multipart upload. Any failure here (including a
+/// failure to complete) is surfaced to the caller so it can abort the upload.
+async fn complete_multipart(
+    client: &aws_sdk_s3::Client,
+    s3_info: &S3Info,
+    body: &[u8],
+    part_size: u64,
+    upload_id: &str,
+    if_match: Option<String>,
+) -> Result<()> {
+    let parts = upload_parts(client, s3_info, body, part_size, upload_id).await?;
+    let completed = aws_sdk_s3::types::CompletedMultipartUpload::builder()
+        .set_parts(Some(parts))
+        .build();
+
+    let mut complete = client
+        .complete_multipart_upload()
+        .bucket(&s3_info.bucket)
+        .key(&s3_info.key)
+        .upload_id(upload_id)
+        .multipart_upload(completed);
+    if let Some(if_match) = if_match {
+        complete = complete.if_match(if_match);
+    }
+
+    complete
+        .send()
+        .await
+        .wrap_err("completing multipart upload")?;
+
+    Ok(())
+}
+
+async fn upload_parts(
+    client: &aws_sdk_s3::Client,
+    s3_info: &S3Info,
+    body: &[u8],
+    part_size: u64,
+    upload_id: &str,
+) -> Result<Vec<aws_sdk_s3::types::CompletedPart>> {
+    let part_size = part_size as usize;
+    let mut parts = Vec::new();
+
+    for (index, chunk) in body.chunks(part_size).enumerate() {
+        let part_number = (index + 1) as i32;
+        let res = client
+            .upload_part()
+            .bucket(&s3_info.bucket)
+            .key(&s3_info.key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(chunk.to_vec().into())
+            .send()
+            .await
+            .wrap_err_with(|| format!("uploading part {part_number}"))?;
+
+        let e_tag = res
+            .e_tag()
+            .ok_or_else(|| eyre::eyre!("upload_part response for part {part_number} missing e_tag"))?
+            .to_string();
+
+        parts.push(
+            aws_sdk_s3::types::CompletedPart::builder()
+                .part_number(part_number)
+                .e_tag(e_tag)
+                .build(),
+        );
+    }
+
+    Ok(parts)
+}